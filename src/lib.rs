@@ -5,14 +5,18 @@ use std::{
 };
 
 use ipnet::{Ipv4Net, Ipv6Net};
-use iprange::IpRange;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use tabled::{Table, Tabled};
 use uuid::Uuid;
 use worker::*;
 
 const CF_CACHE_STATUS_HEADER: &str = "cf-cache-status";
 const AWS_RANGE_URL: &str = "https://ip-ranges.amazonaws.com/ip-ranges.json";
+const RANGE_TTL_SECONDS_VAR: &str = "RANGE_TTL_SECONDS";
+// AWS publishes updates to ip-ranges.json a few times a day; 12h mirrors the
+// refresh cadence the CloudFront S3 filter example uses for its own cache.
+const DEFAULT_RANGE_TTL_SECONDS: u64 = 12 * 60 * 60;
 
 lazy_static! {
     static ref INSTANCE_ID: String = Uuid::new_v4().to_string();
@@ -23,10 +27,62 @@ lazy_static! {
     static ref AWS_RESPONSE: RwLock<Option<Arc<AWSResponse>>> = RwLock::new(None);
 }
 
+/// Extension trait mirroring `worker::Response::with_cors`, adding the
+/// cache-control and security header set every route (lookup, `/version`,
+/// and all error paths) responds with, following the response-header
+/// fairing approach vaultwarden's `util.rs` uses.
+trait ResponseHeadersExt {
+    fn with_standard_headers(
+        self,
+        cache_control: &str,
+        cf_cache_status: Option<&str>,
+        vary: Option<&str>,
+    ) -> Result<Response>;
+}
+
+impl ResponseHeadersExt for Response {
+    fn with_standard_headers(
+        mut self,
+        cache_control: &str,
+        cf_cache_status: Option<&str>,
+        vary: Option<&str>,
+    ) -> Result<Response> {
+        let headers = self.headers_mut();
+
+        headers.set("Cache-Control", cache_control)?;
+        headers.set("X-Content-Type-Options", "nosniff")?;
+        headers.set("Referrer-Policy", "no-referrer")?;
+        headers.set(
+            "Permissions-Policy",
+            "geolocation=(), camera=(), microphone=()",
+        )?;
+        headers.set("X-Instance-Id", &INSTANCE_ID)?;
+
+        if let Some(cf_cache_status) = cf_cache_status {
+            headers.set(CF_CACHE_STATUS_HEADER, cf_cache_status)?;
+        }
+
+        if let Some(vary) = vary {
+            headers.set("Vary", vary)?;
+        }
+
+        Ok(self)
+    }
+}
+
 #[derive(Debug)]
 pub struct AWSResponse {
     pub ranges: AWSIpRanges,
     pub cf_cache_status: String,
+    pub fetched_at_ms: u64,
+    ipv4_trie: TrieNode,
+    ipv6_trie: TrieNode,
+}
+
+impl AWSResponse {
+    fn age_seconds(&self) -> u64 {
+        Date::now().as_millis().saturating_sub(self.fetched_at_ms) / 1000
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,8 +96,6 @@ pub struct AWSIpRanges {
 pub struct Ipv4Prefix {
     #[serde(rename = "ip_prefix")]
     pub ip_prefix: String,
-    #[serde(skip)]
-    pub ipv4_prefix_compute: IpRange<Ipv4Net>,
     pub region: String,
     pub service: String,
     #[serde(rename = "network_border_group")]
@@ -52,14 +106,64 @@ pub struct Ipv4Prefix {
 pub struct Ipv6Prefix {
     #[serde(rename = "ipv6_prefix")]
     pub ipv6_prefix: String,
-    #[serde(skip)]
-    pub ipv6_prefix_compute: IpRange<Ipv6Net>,
     pub region: String,
     pub service: String,
     #[serde(rename = "network_border_group")]
     pub network_border_group: String,
 }
 
+/// A node of the binary radix trie used to match an address against the
+/// thousands of published AWS prefixes in O(bits) instead of a linear scan.
+///
+/// Each edge represents one bit of a prefix, walked from the most
+/// significant bit down; `entries` holds the indices (into
+/// `AWSIpRanges::prefixes`/`ipv6_prefixes`) of every prefix that terminates
+/// at that node. AWS lists overlapping prefixes for the same address (e.g.
+/// AMAZON plus a specific service), so a query collects the entries at every
+/// node along the path it walks rather than stopping at the first match.
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: [Option<Box<TrieNode>>; 2],
+    entries: Vec<usize>,
+}
+
+impl TrieNode {
+    fn insert(&mut self, bits: impl Iterator<Item = bool>, entry_index: usize) {
+        let mut node = self;
+        for bit in bits {
+            node = node.children[bit as usize].get_or_insert_with(Default::default);
+        }
+        node.entries.push(entry_index);
+    }
+
+    /// Walks `bits` from the root, collecting the entries stored at every
+    /// node reached. Reaching a node proves the address shares that node's
+    /// prefix, so every entry on the path is a match; the walk stops as soon
+    /// as the next bit has no corresponding child.
+    fn matches(&self, bits: impl Iterator<Item = bool>) -> Vec<usize> {
+        let mut node = self;
+        let mut found = node.entries.clone();
+
+        for bit in bits {
+            match &node.children[bit as usize] {
+                Some(child) => {
+                    node = child;
+                    found.extend_from_slice(&node.entries);
+                }
+                None => break,
+            }
+        }
+
+        found
+    }
+}
+
+/// Iterates the most-significant `len` bits of `bytes` as booleans, MSB
+/// first, for feeding into [`TrieNode::insert`]/[`TrieNode::matches`].
+fn msb_bits(bytes: &[u8], len: u8) -> impl Iterator<Item = bool> + '_ {
+    (0..len as usize).map(move |i| (bytes[i / 8] >> (7 - i % 8)) & 1 == 1)
+}
+
 #[derive(Serialize)]
 pub struct VersionJSON<'a> {
     pub instance_id: &'a str,
@@ -76,54 +180,145 @@ pub struct APIResponse<'a> {
     pub matches: Vec<APIMatch<'a>>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Tabled)]
 pub struct APIMatch<'a> {
     #[serde(rename = "ip_prefix")]
+    #[tabled(rename = "ip_prefix")]
     pub ip_prefix: &'a str,
     pub region: &'a str,
     pub service: &'a str,
     #[serde(rename = "network_border_group")]
+    #[tabled(rename = "network_border_group")]
     pub network_border_group: &'a str,
 }
 
-pub async fn fetch_aws_ranges() -> Result<(Arc<AWSResponse>, bool)> {
-    let mut aws_response_storage: Option<Arc<AWSResponse>> = {
+/// Narrows `ip_match`/`ipv4_match`/`ipv6_match` down to prefixes whose
+/// `region`/`service`/`network_border_group` equal (case-insensitive) one of
+/// the requested values. Mirrors the region narrowing the CloudFront S3
+/// filter example applies before matching, but exposed per-request via query
+/// parameters instead of being fixed at build time.
+#[derive(Debug, Default)]
+pub struct MatchFilters {
+    regions: Option<Vec<String>>,
+    services: Option<Vec<String>>,
+    network_border_groups: Option<Vec<String>>,
+}
+
+impl MatchFilters {
+    pub fn from_query(request_url: &Url) -> Self {
+        Self {
+            regions: Self::parse_values(request_url, "region"),
+            services: Self::parse_values(request_url, "service"),
+            network_border_groups: Self::parse_values(request_url, "network_border_group"),
+        }
+    }
+
+    fn parse_values(request_url: &Url, name: &str) -> Option<Vec<String>> {
+        let values: Vec<String> = request_url
+            .query_pairs()
+            .find(|(key, _)| key == name)?
+            .1
+            .split(',')
+            .map(|value| value.trim().to_owned())
+            .filter(|value| !value.is_empty())
+            .collect();
+
+        (!values.is_empty()).then_some(values)
+    }
+
+    fn matches(&self, region: &str, service: &str, network_border_group: &str) -> bool {
+        Self::field_matches(&self.regions, region)
+            && Self::field_matches(&self.services, service)
+            && Self::field_matches(&self.network_border_groups, network_border_group)
+    }
+
+    fn field_matches(filter: &Option<Vec<String>>, value: &str) -> bool {
+        match filter {
+            Some(values) => values
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(value)),
+            None => true,
+        }
+    }
+}
+
+/// Returns the cached AWS ranges. When the cache is older than
+/// `ttl_seconds`, the stale copy is served immediately and a refresh is
+/// kicked off via `ctx.wait_until` so it runs after the response is sent
+/// instead of adding its latency to this request; only a genuinely empty
+/// cache (cold start) pays for a synchronous fetch. The second element of
+/// the tuple is `true` when the response was served from the existing cache
+/// rather than a freshly completed fetch.
+pub async fn fetch_aws_ranges(
+    ttl_seconds: u64,
+    ctx: &worker::Context,
+) -> Result<(Arc<AWSResponse>, bool)> {
+    let cached: Option<Arc<AWSResponse>> = {
         let read_lock = AWS_RESPONSE.read().unwrap();
         read_lock.as_ref().map(Arc::clone)
     };
 
-    let is_local = aws_response_storage.is_some();
-
-    if aws_response_storage.is_none() {
-        // Fetch
-        let mut fetch_options = RequestInit::default();
-        fetch_options.cf.cache_everything = Some(true);
-        fetch_options.cf.cache_ttl = Some(3600);
+    let is_fresh = cached
+        .as_ref()
+        .map(|cached| cached.age_seconds() < ttl_seconds)
+        .unwrap_or(false);
+
+    match cached {
+        Some(cached) if is_fresh => Ok((cached, true)),
+        Some(stale) => {
+            ctx.wait_until(async {
+                if let Err(err) = refresh_aws_ranges().await {
+                    console_error!(
+                        "Background AWS ranges refresh failed, will retry stale cache: {:?}",
+                        err
+                    );
+                }
+            });
+            Ok((stale, true))
+        }
+        None => refresh_aws_ranges()
+            .await
+            .map(|aws_response| (aws_response, false)),
+    }
+}
 
-        let fetch_request = Request::new_with_init(AWS_RANGE_URL, &fetch_options)?;
-        let mut fetch_request = Fetch::Request(fetch_request).send().await?;
-        let ranges: AWSIpRanges = fetch_request.json().await?;
+/// Fetches `ip-ranges.json`, recomputes the matcher structures and swaps the
+/// result into the shared cache under the write lock.
+async fn refresh_aws_ranges() -> Result<Arc<AWSResponse>> {
+    let mut fetch_options = RequestInit::default();
+    fetch_options.cf.cache_everything = Some(true);
+    fetch_options.cf.cache_ttl = Some(3600);
 
-        let response_headers = fetch_request.headers();
-        let cf_header = response_headers.get(CF_CACHE_STATUS_HEADER)?;
-        let cf_cache_status = cf_header.unwrap_or_else(|| "UNKNOWN".to_owned());
+    let fetch_request = Request::new_with_init(AWS_RANGE_URL, &fetch_options)?;
+    let mut fetch_request = Fetch::Request(fetch_request).send().await?;
+    let ranges: AWSIpRanges = fetch_request.json().await?;
 
-        let aws_response = calculate_aws_response(ranges, cf_cache_status);
-        let aws_response = Arc::new(aws_response);
+    let response_headers = fetch_request.headers();
+    let cf_header = response_headers.get(CF_CACHE_STATUS_HEADER)?;
+    let cf_cache_status = cf_header.unwrap_or_else(|| "UNKNOWN".to_owned());
 
-        let mut write_lock = AWS_RESPONSE.write().unwrap();
-        write_lock.replace(Arc::clone(&aws_response));
+    let aws_response = calculate_aws_response(ranges, cf_cache_status, Date::now().as_millis());
+    let aws_response = Arc::new(aws_response);
 
-        aws_response_storage.replace(aws_response);
-    }
+    let mut write_lock = AWS_RESPONSE.write().unwrap();
+    write_lock.replace(Arc::clone(&aws_response));
 
-    let aws_response_storage = aws_response_storage.unwrap();
+    Ok(aws_response)
+}
 
-    Ok((aws_response_storage, is_local))
+/// Proactively warms/refreshes the cache on a schedule. Only the cache of the
+/// instance that handles the scheduled event is refreshed; an instance whose
+/// cache is still empty fetches synchronously on its first request. Configure
+/// the cron trigger (e.g. every hour) in `wrangler.toml`.
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, _env: Env, _ctx: ScheduleContext) {
+    if let Err(err) = refresh_aws_ranges().await {
+        console_error!("Scheduled AWS ranges refresh failed: {:?}", err);
+    }
 }
 
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, worker_ctx: worker::Context) -> Result<Response> {
     // Optionally, use the Router to handle matching endpoints, use ":name" placeholders, or "*name"
     // catch-alls to match on specific patterns. Alternatively, use `Router::with_data(D)` to
     // provide arbitrary data that will be accessible in each route via the `ctx.data()` method.
@@ -133,65 +328,130 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
     // functionality and a `RouteContext` which you can use to  and get route parameters and
     // Environment bindings like KV Stores, Durable Objects, Secrets, and Variables.
     router
-        .get_async("/", |req, _| async move {
-            let request_url = match req.url() {
-                Ok(url) => url,
-                Err(err) => {
-                    console_error!("Unknown URL parse error: {:?}", err);
-                    return Response::error("Unknown error", 500)?.with_cors(&CORS_HEADERS);
+        .get_async("/", move |req, ctx| {
+            // `worker_ctx` (the top-level `Context`, with `wait_until`) is
+            // distinct from `ctx` (the router's per-route `RouteContext`);
+            // clone it so the background refresh can outlive this response.
+            let worker_ctx = worker_ctx.clone();
+            async move {
+                let request_url = match req.url() {
+                    Ok(url) => url,
+                    Err(err) => {
+                        console_error!("Unknown URL parse error: {:?}", err);
+                        return Response::error("Unknown error", 500)?
+                            .with_cors(&CORS_HEADERS)?
+                            .with_standard_headers("no-store", None, None);
+                    }
+                };
+
+                let ip_param: Option<Cow<str>> =
+                    request_url.query_pairs().find(|i| i.0 == "ip").map(|i| i.1);
+
+                let (ip_param, ip_from_connecting_ip) = match ip_param {
+                    Some(ip_param) => (ip_param, false),
+                    // No "ip" query parameter: fall back to the caller's own
+                    // connecting address, turning a bare `GET /` into "which AWS
+                    // region am I talking from?".
+                    None => match connecting_ip(&req) {
+                        Ok(Some(connecting_ip)) => (Cow::Owned(connecting_ip), true),
+                        Ok(None) => {
+                            return Response::error(r#""ip" parameter is missing!"#, 400)?
+                                .with_cors(&CORS_HEADERS)?
+                                .with_standard_headers("no-store", None, None)
+                        }
+                        Err(err) => {
+                            console_error!("Unable to read connecting IP headers: {:?}", err);
+                            return Response::error(r#""ip" parameter is missing!"#, 400)?
+                                .with_cors(&CORS_HEADERS)?
+                                .with_standard_headers("no-store", None, None);
+                        }
+                    },
+                };
+
+                if ip_param.is_empty() {
+                    return Response::error(r#""ip" parameter is empty!"#, 400)?
+                        .with_cors(&CORS_HEADERS)?
+                        .with_standard_headers("no-store", None, None);
                 }
-            };
 
-            let ip_param: Option<Cow<str>> =
-                request_url.query_pairs().find(|i| i.0 == "ip").map(|i| i.1);
-
-            let ip_param = match ip_param {
-                Some(ip_param) => ip_param,
-                None => {
-                    return Response::error(r#""ip" parameter is missing!"#, 400)?
-                        .with_cors(&CORS_HEADERS)
+                let ip_address = match ip_param.parse::<IpAddr>() {
+                    Ok(ip_param) => ip_param,
+                    Err(err) => {
+                        console_error!("IP parameter is not valid: {:?}", err);
+                        return Response::error(
+                            r#""ip" parameter is not a valid IP address!"#,
+                            400,
+                        )?
+                        .with_cors(&CORS_HEADERS)?
+                        .with_standard_headers("no-store", None, None);
+                    }
+                };
+
+                let ttl_seconds = ctx
+                    .var(RANGE_TTL_SECONDS_VAR)
+                    .ok()
+                    .and_then(|v| v.to_string().parse::<u64>().ok())
+                    .unwrap_or(DEFAULT_RANGE_TTL_SECONDS);
+
+                let (aws_response, is_local) =
+                    match fetch_aws_ranges(ttl_seconds, &worker_ctx).await {
+                        Ok(aws_response) => aws_response,
+                        Err(err) => {
+                            console_error!("Unable to fetch AWS ranges: {:?}", err);
+                            return Response::error("Unable to fetch AWS ranges", 500)?
+                                .with_cors(&CORS_HEADERS)?
+                                .with_standard_headers("no-store", None, None);
+                        }
+                    };
+
+                let cache_status = if is_local {
+                    "LOCAL"
+                } else {
+                    aws_response.cf_cache_status.as_str()
+                };
+
+                let match_filters = MatchFilters::from_query(&request_url);
+
+                // Check if we have matches against ip_address value
+                let matches = ip_match(&aws_response, &ip_address, &match_filters);
+
+                let api_response = APIResponse {
+                    requested_ip: &ip_param,
+                    cache_status,
+                    matches,
+                };
+
+                // A bare `GET /` resolves to the caller's own connecting IP, so
+                // the exact same URL must answer differently per caller: never
+                // let an intermediary cache share one client's region with
+                // another's.
+                let cache_control = if ip_from_connecting_ip {
+                    "private, no-store".to_owned()
+                } else {
+                    let remaining_ttl = ttl_seconds.saturating_sub(aws_response.age_seconds());
+                    format!("public, max-age={remaining_ttl}")
+                };
+                let cf_cache_status = Some(aws_response.cf_cache_status.as_str());
+
+                // The body shape depends on `Accept`/`format=`, and (for the
+                // bare-`/` case) on the connecting-IP headers, so any cache
+                // sitting in front of this response must key on them too.
+                let vary = if ip_from_connecting_ip {
+                    "Accept, CF-Connecting-IP, CF-Connecting-IPv6"
+                } else {
+                    "Accept"
+                };
+
+                if wants_text_format(&request_url, &req) {
+                    Response::ok(render_text_table(&api_response))?
+                        .with_cors(&CORS_HEADERS)?
+                        .with_standard_headers(&cache_control, cf_cache_status, Some(vary))
+                } else {
+                    Response::from_json(&api_response)?
+                        .with_cors(&CORS_HEADERS)?
+                        .with_standard_headers(&cache_control, cf_cache_status, Some(vary))
                 }
-            };
-
-            if ip_param.is_empty() {
-                return Response::error(r#""ip" parameter is empty!"#, 400)?
-                    .with_cors(&CORS_HEADERS);
             }
-
-            let ip_address = match ip_param.parse::<IpAddr>() {
-                Ok(ip_param) => ip_param,
-                Err(err) => {
-                    console_error!("IP parameter is not valid: {:?}", err);
-                    return Response::error(r#""ip" parameter is not a valid IP address!"#, 400)?
-                        .with_cors(&CORS_HEADERS);
-                }
-            };
-
-            let (aws_response, is_local) = match fetch_aws_ranges().await {
-                Ok(aws_response) => aws_response,
-                Err(err) => {
-                    console_error!("Unable to fetch AWS ranges: {:?}", err);
-                    return Response::error("Unable to fetch AWS ranges", 500)?
-                        .with_cors(&CORS_HEADERS);
-                }
-            };
-
-            let cache_status = if is_local {
-                "LOCAL"
-            } else {
-                aws_response.cf_cache_status.as_str()
-            };
-
-            // Check if we have matches against ip_address value
-            let matches = ip_match(&aws_response.ranges, &ip_address);
-
-            let api_response = APIResponse {
-                requested_ip: &ip_param,
-                cache_status,
-                matches,
-            };
-
-            Response::from_json(&api_response)?.with_cors(&CORS_HEADERS)
         })
         .get("/version", |_, ctx| {
             let local_version = env!("CARGO_PKG_VERSION");
@@ -203,42 +463,122 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 workers_version,
             };
 
-            Response::from_json(&version_response)?.with_cors(&CORS_HEADERS)
+            Response::from_json(&version_response)?
+                .with_cors(&CORS_HEADERS)?
+                .with_standard_headers("no-store", None, None)
         })
         .run(req, env)
         .await
 }
 
-fn calculate_aws_response(mut ranges: AWSIpRanges, cf_cache_status: String) -> AWSResponse {
-    // Compute all ranges
-    ranges.prefixes.iter_mut().for_each(|range| {
-        range.ipv4_prefix_compute = [range.ip_prefix.parse::<Ipv4Net>().unwrap()]
-            .into_iter()
-            .collect();
-    });
-    ranges.ipv6_prefixes.iter_mut().for_each(|range| {
-        range.ipv6_prefix_compute = [range.ipv6_prefix.parse::<Ipv6Net>().unwrap()]
-            .into_iter()
-            .collect();
-    });
+fn calculate_aws_response(
+    ranges: AWSIpRanges,
+    cf_cache_status: String,
+    fetched_at_ms: u64,
+) -> AWSResponse {
+    let mut ipv4_trie = TrieNode::default();
+    for (index, range) in ranges.prefixes.iter().enumerate() {
+        let network = range.ip_prefix.parse::<Ipv4Net>().unwrap();
+        let octets = network.network().octets();
+        let bits = msb_bits(&octets, network.prefix_len());
+        ipv4_trie.insert(bits, index);
+    }
+
+    let mut ipv6_trie = TrieNode::default();
+    for (index, range) in ranges.ipv6_prefixes.iter().enumerate() {
+        let network = range.ipv6_prefix.parse::<Ipv6Net>().unwrap();
+        let octets = network.network().octets();
+        let bits = msb_bits(&octets, network.prefix_len());
+        ipv6_trie.insert(bits, index);
+    }
 
     AWSResponse {
         ranges,
         cf_cache_status,
+        fetched_at_ms,
+        ipv4_trie,
+        ipv6_trie,
+    }
+}
+
+/// Resolves the caller's real address from the Cloudflare connecting-IP
+/// headers: `CF-Connecting-IP` carries the actual connecting address
+/// (IPv4 or IPv6) and is always set by Cloudflare, so `CF-Connecting-IPv6`
+/// (the mapped IPv6 representation) is only consulted if it's somehow
+/// absent. Used to answer "which AWS region am I talking from?" when no
+/// `ip` query parameter is given.
+fn connecting_ip(req: &Request) -> Result<Option<String>> {
+    let headers = req.headers();
+
+    if let Some(ipv4) = headers.get("CF-Connecting-IP")? {
+        return Ok(Some(ipv4));
+    }
+
+    headers.get("CF-Connecting-IPv6")
+}
+
+/// Whether the caller asked for the `tabled` plaintext rendering instead of
+/// JSON, via a `format=text` query parameter or an `Accept: text/plain`
+/// header. When a `format` parameter is present it alone decides, and the
+/// `Accept` header is not consulted.
+fn wants_text_format(request_url: &Url, req: &Request) -> bool {
+    let format_param = request_url
+        .query_pairs()
+        .find(|(key, _)| key == "format")
+        .map(|(_, value)| value.eq_ignore_ascii_case("text"));
+
+    if let Some(format_param) = format_param {
+        return format_param;
     }
+
+    req.headers()
+        .get("Accept")
+        .ok()
+        .flatten()
+        .map(|accept| accept.contains("text/plain"))
+        .unwrap_or(false)
+}
+
+/// Renders an `APIResponse` as an aligned `tabled` table, usable directly
+/// from `curl` without piping through a JSON formatter.
+fn render_text_table(api_response: &APIResponse) -> String {
+    let table = if api_response.matches.is_empty() {
+        "(no matches)".to_owned()
+    } else {
+        Table::new(&api_response.matches).to_string()
+    };
+
+    format!(
+        "requested_ip: {}\ncache_status: {}\n\n{}\n",
+        api_response.requested_ip, api_response.cache_status, table
+    )
 }
 
-fn ip_match<'a>(aws_ranges: &'a AWSIpRanges, ip_address: &'a IpAddr) -> Vec<APIMatch<'a>> {
+fn ip_match<'a>(
+    aws_response: &'a AWSResponse,
+    ip_address: &IpAddr,
+    filters: &MatchFilters,
+) -> Vec<APIMatch<'a>> {
     match ip_address {
-        IpAddr::V4(ipv4) => ipv4_match(&aws_ranges.prefixes, ipv4),
-        IpAddr::V6(ipv6) => ipv6_match(&aws_ranges.ipv6_prefixes, ipv6),
+        IpAddr::V4(ipv4) => ipv4_match(aws_response, ipv4, filters),
+        IpAddr::V6(ipv6) => ipv6_match(aws_response, ipv6, filters),
     }
 }
 
-fn ipv4_match<'a>(aws_ranges: &'a [Ipv4Prefix], ip_address: &'a Ipv4Addr) -> Vec<APIMatch<'a>> {
-    aws_ranges
-        .iter()
-        .filter(|x| x.ipv4_prefix_compute.contains(ip_address))
+fn ipv4_match<'a>(
+    aws_response: &'a AWSResponse,
+    ip_address: &Ipv4Addr,
+    filters: &MatchFilters,
+) -> Vec<APIMatch<'a>> {
+    let octets = ip_address.octets();
+    let bits = msb_bits(&octets, 32);
+
+    aws_response
+        .ipv4_trie
+        .matches(bits)
+        .into_iter()
+        .filter_map(|index| aws_response.ranges.prefixes.get(index))
+        .filter(|x| filters.matches(&x.region, &x.service, &x.network_border_group))
         .map(|x| APIMatch {
             ip_prefix: &x.ip_prefix,
             region: &x.region,
@@ -248,10 +588,20 @@ fn ipv4_match<'a>(aws_ranges: &'a [Ipv4Prefix], ip_address: &'a Ipv4Addr) -> Vec
         .collect::<Vec<_>>()
 }
 
-fn ipv6_match<'a>(aws_ranges: &'a [Ipv6Prefix], ip_address: &'a Ipv6Addr) -> Vec<APIMatch<'a>> {
-    aws_ranges
-        .iter()
-        .filter(|x| x.ipv6_prefix_compute.contains(ip_address))
+fn ipv6_match<'a>(
+    aws_response: &'a AWSResponse,
+    ip_address: &Ipv6Addr,
+    filters: &MatchFilters,
+) -> Vec<APIMatch<'a>> {
+    let octets = ip_address.octets();
+    let bits = msb_bits(&octets, 128);
+
+    aws_response
+        .ipv6_trie
+        .matches(bits)
+        .into_iter()
+        .filter_map(|index| aws_response.ranges.ipv6_prefixes.get(index))
+        .filter(|x| filters.matches(&x.region, &x.service, &x.network_border_group))
         .map(|x| APIMatch {
             ip_prefix: &x.ipv6_prefix,
             region: &x.region,
@@ -270,7 +620,7 @@ mod tests {
         let ranges: AWSIpRanges =
             serde_json::from_str(test_aws_ranges).expect("JSON deserialization error");
 
-        calculate_aws_response(ranges, "TEST".to_owned())
+        calculate_aws_response(ranges, "TEST".to_owned(), 0)
     }
 
     #[test]
@@ -279,12 +629,12 @@ mod tests {
 
         // Match expected
         let ip_address: IpAddr = "52.1.1.1".parse().unwrap();
-        let result_ranges = ip_match(&aws_response.ranges, &ip_address);
+        let result_ranges = ip_match(&aws_response, &ip_address, &MatchFilters::default());
         assert!(!result_ranges.is_empty());
 
         // Match not expected
         let ip_address: IpAddr = "8.8.8.8".parse().unwrap();
-        let result_ranges = ip_match(&aws_response.ranges, &ip_address);
+        let result_ranges = ip_match(&aws_response, &ip_address, &MatchFilters::default());
         assert!(result_ranges.is_empty());
     }
 
@@ -294,12 +644,107 @@ mod tests {
 
         // Match expected
         let ip_address: IpAddr = "2406:da60:c000::00".parse().unwrap();
-        let result_ranges = ip_match(&aws_response.ranges, &ip_address);
+        let result_ranges = ip_match(&aws_response, &ip_address, &MatchFilters::default());
         assert!(!result_ranges.is_empty());
 
         // Match not expected
         let ip_address: IpAddr = "2206:de60:c000::00".parse().unwrap();
-        let result_ranges = ip_match(&aws_response.ranges, &ip_address);
+        let result_ranges = ip_match(&aws_response, &ip_address, &MatchFilters::default());
+        assert!(result_ranges.is_empty());
+    }
+
+    /// AWS publishes an address under both a broad `AMAZON` prefix and a
+    /// narrower, service-specific prefix, so a single address is expected to
+    /// land under multiple overlapping entries. Built in code rather than
+    /// from the JSON fixture so the overlap is exact and doesn't depend on
+    /// what happens to be in `ip-ranges.json` at test time.
+    fn overlapping_aws_response() -> AWSResponse {
+        let ranges = AWSIpRanges {
+            prefixes: vec![
+                Ipv4Prefix {
+                    ip_prefix: "52.0.0.0/8".to_owned(),
+                    region: "us-east-1".to_owned(),
+                    service: "AMAZON".to_owned(),
+                    network_border_group: "us-east-1".to_owned(),
+                },
+                Ipv4Prefix {
+                    ip_prefix: "52.1.0.0/16".to_owned(),
+                    region: "us-east-1".to_owned(),
+                    service: "EC2".to_owned(),
+                    network_border_group: "us-east-1".to_owned(),
+                },
+                Ipv4Prefix {
+                    ip_prefix: "10.0.0.0/8".to_owned(),
+                    region: "eu-west-1".to_owned(),
+                    service: "S3".to_owned(),
+                    network_border_group: "eu-west-1".to_owned(),
+                },
+            ],
+            ipv6_prefixes: vec![],
+        };
+
+        calculate_aws_response(ranges, "TEST".to_owned(), 0)
+    }
+
+    #[test]
+    fn test_trie_collects_all_overlapping_prefixes() {
+        let aws_response = overlapping_aws_response();
+
+        // 52.1.1.1 falls under both the broad AMAZON/8 prefix and the
+        // narrower EC2/16 prefix: the trie must return both, not just the
+        // longest (or first) match.
+        let ip_address: IpAddr = "52.1.1.1".parse().unwrap();
+        let result_ranges = ip_match(&aws_response, &ip_address, &MatchFilters::default());
+
+        assert_eq!(result_ranges.len(), 2);
+        assert!(result_ranges.iter().any(|m| m.service == "AMAZON"));
+        assert!(result_ranges.iter().any(|m| m.service == "EC2"));
+
+        // An address outside every prefix matches nothing.
+        let ip_address: IpAddr = "8.8.8.8".parse().unwrap();
+        let result_ranges = ip_match(&aws_response, &ip_address, &MatchFilters::default());
+        assert!(result_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_service_filter_narrows_matches() {
+        let aws_response = overlapping_aws_response();
+        let ip_address: IpAddr = "52.1.1.1".parse().unwrap();
+
+        let filters =
+            MatchFilters::from_query(&Url::parse("https://example.com/?service=ec2").unwrap());
+        let result_ranges = ip_match(&aws_response, &ip_address, &filters);
+        assert_eq!(result_ranges.len(), 1);
+        assert_eq!(result_ranges[0].service, "EC2");
+
+        let filters =
+            MatchFilters::from_query(&Url::parse("https://example.com/?service=rds").unwrap());
+        let result_ranges = ip_match(&aws_response, &ip_address, &filters);
         assert!(result_ranges.is_empty());
     }
+
+    #[test]
+    fn test_render_text_table() {
+        let aws_response = overlapping_aws_response();
+        let ip_address: IpAddr = "52.1.1.1".parse().unwrap();
+        let matches = ip_match(&aws_response, &ip_address, &MatchFilters::default());
+
+        let api_response = APIResponse {
+            requested_ip: "52.1.1.1",
+            cache_status: "TEST",
+            matches,
+        };
+
+        let table = render_text_table(&api_response);
+        assert!(table.contains("52.1.1.1"));
+        assert!(table.contains("EC2"));
+        assert!(table.contains("AMAZON"));
+
+        let empty_response = APIResponse {
+            requested_ip: "8.8.8.8",
+            cache_status: "TEST",
+            matches: Vec::new(),
+        };
+        assert!(render_text_table(&empty_response).contains("no matches"));
+    }
 }